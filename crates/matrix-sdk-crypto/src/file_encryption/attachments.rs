@@ -14,13 +14,18 @@
 
 use std::{
     collections::BTreeMap,
-    io::{Error as IoError, ErrorKind, Read},
+    io::{Error as IoError, ErrorKind, Read, Seek, SeekFrom},
 };
 
 use aes::{
     cipher::{generic_array::GenericArray, FromBlockCipher, NewBlockCipher, StreamCipher},
     Aes256, Aes256Ctr,
 };
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes256Gcm, Nonce,
+};
+use argon2::Argon2;
 use base64::DecodeError;
 use getrandom::getrandom;
 use ruma::{
@@ -32,49 +37,366 @@ use sha2::{Digest, Sha256};
 use thiserror::Error;
 use zeroize::Zeroizing;
 
-const IV_SIZE: usize = 16;
+#[cfg(any(feature = "async-read", feature = "tokio"))]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(feature = "async-read")]
+use futures_util::io::AsyncRead as FuturesAsyncRead;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead as TokioAsyncRead, ReadBuf};
+
 const KEY_SIZE: usize = 32;
-const VERSION: &str = "v2";
+
+/// The legacy attachment format: AES-256-CTR with a trailing SHA-256 hash of
+/// the whole ciphertext.
+const VERSION_V2: &str = "v2";
+/// The chunked, authenticated attachment format: AES-256-GCM applied
+/// independently to fixed-size chunks, so corruption and truncation are
+/// detected as soon as the offending chunk is read instead of only after the
+/// whole file has been streamed.
+const VERSION_V3: &str = "v3";
+
+/// The size, in bytes, of a plaintext chunk that `v3` encrypts as a single
+/// AES-256-GCM operation.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The size, in bytes, of the GCM authentication tag appended to every `v3`
+/// chunk's ciphertext.
+const TAG_SIZE: usize = 16;
+/// The size, in bytes, of the random nonce prefix that, combined with the
+/// little-endian chunk counter, forms the 12-byte GCM nonce for each `v3`
+/// chunk.
+const NONCE_PREFIX_SIZE: usize = 8;
+
+/// Magic bytes identifying a blob produced by
+/// [`MediaEncryptionInfo::export_encrypted()`].
+const EXPORT_MAGIC: &[u8; 4] = b"MXMK";
+/// The version of the export frame layout.
+const EXPORT_VERSION: u8 = 1;
+/// The size, in bytes, of the random Argon2id salt stored in an export frame.
+const EXPORT_SALT_SIZE: usize = 16;
+/// The size, in bytes, of the random AES-256-GCM nonce stored in an export
+/// frame.
+const EXPORT_NONCE_SIZE: usize = 12;
+
+/// Derive the 32-byte key used to wrap an exported [`MediaEncryptionInfo`]
+/// from a passphrase and salt, using Argon2id.
+fn derive_export_key(passphrase: &str, salt: &[u8]) -> Zeroizing<[u8; KEY_SIZE]> {
+    let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut *key)
+        .expect("Argon2id key derivation with valid parameters failed");
+
+    key
+}
+
+/// The header line of a [`MediaEncryptionInfo::to_armored_string()`] block.
+const ARMOR_HEADER: &str = "-----BEGIN MATRIX MEDIA KEY-----";
+/// The footer line of a [`MediaEncryptionInfo::to_armored_string()`] block.
+const ARMOR_FOOTER: &str = "-----END MATRIX MEDIA KEY-----";
+/// The number of Base85 characters per wrapped line in an armored block.
+const ARMOR_LINE_WIDTH: usize = 64;
+
+/// Compute the OpenPGP-style CRC-24 checksum (poly `0x1864CFB`, init
+/// `0xB704CE`) used to detect corruption in an armored block.
+fn crc24(data: &[u8]) -> u32 {
+    const INIT: u32 = 0x00B7_04CE;
+    const POLY: u32 = 0x0186_4CFB;
+
+    let mut crc = INIT;
+
+    for &byte in data {
+        crc ^= (byte as u32) << 16;
+
+        for _ in 0..8 {
+            crc <<= 1;
+
+            if crc & 0x0100_0000 != 0 {
+                crc ^= POLY;
+            }
+        }
+    }
+
+    crc & 0x00FF_FFFF
+}
+
+/// Build the 12-byte GCM nonce for chunk `index`.
+///
+/// The final chunk of a `v3` attachment is authenticated under a nonce with
+/// the top bit of the counter flipped, so that a decryptor can tell a
+/// genuine end-of-file apart from an attacker truncating the stream right on
+/// a chunk boundary.
+fn chunk_nonce(prefix: [u8; NONCE_PREFIX_SIZE], index: u32, is_final: bool) -> [u8; 12] {
+    let counter = if is_final { index | 0x8000_0000 } else { index };
+
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&prefix);
+    nonce[NONCE_PREFIX_SIZE..].copy_from_slice(&counter.to_le_bytes());
+
+    nonce
+}
+
+/// The per-chunk AES-256-GCM state shared by the `v3` encryptor and
+/// decryptor.
+struct GcmState {
+    cipher: Aes256Gcm,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    chunk_index: u32,
+    /// The total plaintext length, used to resolve `SeekFrom::End`.
+    ///
+    /// Only needed to seek relative to the end of the attachment, so a `v3`
+    /// blob that omits it can still be read start-to-end or seeked to an
+    /// absolute or relative-to-current offset; `new()` doesn't require it.
+    /// It's taken from [`MediaEncryptionInfo::plaintext_len`] as supplied by
+    /// the caller and isn't bound into any GCM tag, so a `SeekFrom::End` seek
+    /// trusts it unauthenticated -- acceptable since every chunk's content is
+    /// still independently authenticated, but worth keeping in mind.
+    plaintext_len: Option<u64>,
+    /// Ciphertext (plus tag) accumulated for the chunk currently being read,
+    /// across however many reads it takes to fill it. Kept as a field,
+    /// rather than a local variable, so that the async `poll_read` impls can
+    /// resume accumulating a chunk across wake-ups.
+    chunk_buf: Vec<u8>,
+}
+
+/// The decryption backend, chosen based on the `version` of the
+/// [`MediaEncryptionInfo`] that was used to construct the
+/// [`AttachmentDecryptor`].
+enum DecryptorBackend {
+    /// `v2`: a single AES-256-CTR keystream over the whole file, integrity
+    /// checked with one SHA-256 hash at the end.
+    Ctr { aes: Aes256Ctr, sha: Sha256, expected_hash: Vec<u8> },
+    /// `v3`: independently authenticated, fixed-size chunks.
+    Gcm {
+        state: GcmState,
+        /// Plaintext of the chunk we're currently handing out, and how much
+        /// of it has already been returned to the caller.
+        output: Vec<u8>,
+        output_pos: usize,
+        /// Set once we've authenticated a chunk that was sealed with the
+        /// final-chunk nonce.
+        finished: bool,
+        /// The caller's current position in the plaintext stream, used to
+        /// resolve `SeekFrom::Current`.
+        position: u64,
+    },
+}
+
+impl GcmState {
+    /// Block on `inner` until the chunk currently being accumulated in
+    /// `self.chunk_buf` is complete, then decrypt and authenticate it.
+    fn read<R: Read>(
+        &mut self,
+        inner: &mut R,
+        output: &mut Vec<u8>,
+        finished: &mut bool,
+    ) -> std::io::Result<()> {
+        loop {
+            let want = CHUNK_SIZE + TAG_SIZE - self.chunk_buf.len();
+            let mut scratch = vec![0u8; want];
+            let read_bytes = inner.read(&mut scratch)?;
+
+            if read_bytes > 0 {
+                self.chunk_buf.extend_from_slice(&scratch[..read_bytes]);
+            }
+
+            if let Some((plaintext, is_final)) = self.try_decrypt_chunk(read_bytes == 0)? {
+                *output = plaintext;
+                *finished = is_final;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Try to decrypt and authenticate the chunk accumulated so far in
+    /// `self.chunk_buf`.
+    ///
+    /// Returns `Ok(None)` if the inner reader hasn't hit EOF yet and we
+    /// haven't accumulated a full chunk. Otherwise consumes `self.chunk_buf`
+    /// and returns the decrypted plaintext along with whether the chunk was
+    /// sealed with the final-chunk nonce.
+    fn try_decrypt_chunk(&mut self, eof: bool) -> std::io::Result<Option<(Vec<u8>, bool)>> {
+        let is_full_chunk = self.chunk_buf.len() == CHUNK_SIZE + TAG_SIZE;
+
+        if !is_full_chunk && !eof {
+            return Ok(None);
+        }
+
+        if self.chunk_buf.is_empty() {
+            return Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                "The attachment ended before a final authenticated chunk was seen",
+            ));
+        }
+
+        if self.chunk_buf.len() < TAG_SIZE {
+            return Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                "The attachment was truncated in the middle of a chunk",
+            ));
+        }
+
+        // A full-sized chunk might still be the final one, so we can't tell
+        // finality from the byte count alone: try the regular nonce first and
+        // only fall back to the final one if that fails to authenticate. A
+        // short chunk can only ever be the final one.
+        let (plaintext, is_final) = if is_full_chunk {
+            let nonce = chunk_nonce(self.nonce_prefix, self.chunk_index, false);
+
+            match self.cipher.decrypt(Nonce::from_slice(&nonce), self.chunk_buf.as_slice()) {
+                Ok(plaintext) => (plaintext, false),
+                Err(_) => (self.decrypt_final()?, true),
+            }
+        } else {
+            (self.decrypt_final()?, true)
+        };
+
+        self.chunk_index += 1;
+        self.chunk_buf.clear();
+
+        Ok(Some((plaintext, is_final)))
+    }
+
+    fn decrypt_final(&self) -> std::io::Result<Vec<u8>> {
+        let nonce = chunk_nonce(self.nonce_prefix, self.chunk_index, true);
+
+        self.cipher.decrypt(Nonce::from_slice(&nonce), self.chunk_buf.as_slice()).map_err(|_| {
+            IoError::new(
+                ErrorKind::InvalidData,
+                "Authentication failed while decrypting an attachment chunk",
+            )
+        })
+    }
+}
 
 /// A wrapper that transparently encrypts anything that implements `Read` as an
 /// Matrix attachment.
-pub struct AttachmentDecryptor<'a, R: 'a + Read> {
+pub struct AttachmentDecryptor<'a, R: 'a> {
     inner: &'a mut R,
-    expected_hash: Vec<u8>,
-    sha: Sha256,
-    aes: Aes256Ctr,
+    backend: DecryptorBackend,
 }
 
-impl<'a, R: 'a + Read + std::fmt::Debug> std::fmt::Debug for AttachmentDecryptor<'a, R> {
+impl<'a, R: 'a + std::fmt::Debug> std::fmt::Debug for AttachmentDecryptor<'a, R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("AttachmentDecryptor")
-            .field("inner", &self.inner)
-            .field("expected_hash", &self.expected_hash)
-            .finish()
+        f.debug_struct("AttachmentDecryptor").field("inner", &self.inner).finish()
     }
 }
 
 impl<'a, R: Read> Read for AttachmentDecryptor<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let read_bytes = self.inner.read(buf)?;
+        match &mut self.backend {
+            DecryptorBackend::Ctr { aes, sha, expected_hash } => {
+                let read_bytes = self.inner.read(buf)?;
+
+                if read_bytes == 0 {
+                    let hash = sha.finalize_reset();
 
-        if read_bytes == 0 {
-            let hash = self.sha.finalize_reset();
+                    if hash.as_slice() == expected_hash.as_slice() {
+                        Ok(0)
+                    } else {
+                        Err(IoError::new(ErrorKind::Other, "Hash mismatch while decrypting"))
+                    }
+                } else {
+                    sha.update(&buf[0..read_bytes]);
+                    aes.apply_keystream(&mut buf[0..read_bytes]);
 
-            if hash.as_slice() == self.expected_hash.as_slice() {
-                Ok(0)
-            } else {
-                Err(IoError::new(ErrorKind::Other, "Hash mismatch while decrypting"))
+                    Ok(read_bytes)
+                }
             }
-        } else {
-            self.sha.update(&buf[0..read_bytes]);
-            self.aes.apply_keystream(&mut buf[0..read_bytes]);
+            DecryptorBackend::Gcm { state, output, output_pos, finished, position } => loop {
+                if *output_pos < output.len() {
+                    let available = output.len() - *output_pos;
+                    let to_copy = available.min(buf.len());
+
+                    buf[..to_copy]
+                        .copy_from_slice(&output[*output_pos..*output_pos + to_copy]);
+                    *output_pos += to_copy;
+                    *position += to_copy as u64;
+
+                    return Ok(to_copy);
+                }
 
-            Ok(read_bytes)
+                if *finished {
+                    return Ok(0);
+                }
+
+                state.read(self.inner, output, finished)?;
+                *output_pos = 0;
+            },
         }
     }
 }
 
+impl<'a, R: Read + Seek> Seek for AttachmentDecryptor<'a, R> {
+    /// Seek to a plaintext offset in a `v3` attachment.
+    ///
+    /// Only attachments using the chunked `v3` format support seeking; `v2`'s
+    /// whole-file hash can only be validated by reading from the start, so
+    /// seeking a `v2` stream returns an `Unsupported` error.
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (state, output, output_pos, finished, position) = match &mut self.backend {
+            DecryptorBackend::Ctr { .. } => {
+                return Err(IoError::new(
+                    ErrorKind::Unsupported,
+                    "Seeking is not supported for v2 attachments",
+                ))
+            }
+            DecryptorBackend::Gcm { state, output, output_pos, finished, position } => {
+                (state, output, output_pos, finished, position)
+            }
+        };
+
+        let target = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => *position as i64 + offset,
+            SeekFrom::End(offset) => {
+                let plaintext_len = state.plaintext_len.ok_or_else(|| {
+                    IoError::new(
+                        ErrorKind::Unsupported,
+                        "Seeking from the end of a v3 attachment requires a plaintext_len hint \
+                         in the encryption info",
+                    )
+                })?;
+
+                plaintext_len as i64 + offset
+            }
+        };
+
+        let target = u64::try_from(target)
+            .map_err(|_| IoError::new(ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+
+        let chunk_index = (target / CHUNK_SIZE as u64) as u32;
+        let chunk_offset = (target % CHUNK_SIZE as u64) as usize;
+        let chunk_byte_pos = chunk_index as u64 * (CHUNK_SIZE + TAG_SIZE) as u64;
+
+        self.inner.seek(SeekFrom::Start(chunk_byte_pos))?;
+        state.chunk_index = chunk_index;
+        state.chunk_buf.clear();
+        *finished = false;
+        output.clear();
+
+        // Eagerly decrypt and authenticate the target chunk so that a seek
+        // landing in a tampered or truncated chunk fails here, rather than
+        // on the next read. With a plaintext_len hint, a seek at or past the
+        // end reads nothing and leaves the stream at EOF. Without a plaintext_len
+        // hint we can't tell a past-the-end seek apart from a valid one
+        // ahead of time, so optimistically try to read and let running out
+        // of chunks surface as the usual truncation error.
+        if state.plaintext_len.map_or(true, |len| target < len) {
+            state.read(self.inner, output, finished)?;
+        } else {
+            *finished = true;
+        }
+
+        *output_pos = chunk_offset.min(output.len());
+        *position = target;
+
+        Ok(target)
+    }
+}
+
 /// Error type for attachment decryption.
 #[derive(Error, Debug)]
 pub enum DecryptorError {
@@ -92,9 +414,23 @@ pub enum DecryptorError {
     /// attachment encryption spec.
     #[error("Unknown version for the encrypted attachment.")]
     UnknownVersion,
+    /// An exported media key blob is too short, has an unrecognized magic
+    /// number or version, or didn't deserialize into a [`MediaEncryptionInfo`]
+    /// once decrypted.
+    #[error("The exported media key has an invalid format")]
+    InvalidExportFormat,
+    /// Decrypting an exported media key failed, either because the
+    /// passphrase was wrong or because the blob was tampered with; AES-GCM
+    /// can't tell these apart.
+    #[error("Failed to decrypt the exported media key: wrong passphrase or corrupted data")]
+    InvalidPassphrase,
+    /// An armored media key block's CRC-24 checksum didn't match its
+    /// contents.
+    #[error("The armored media key's checksum does not match its contents")]
+    ArmorChecksum,
 }
 
-impl<'a, R: Read + 'a> AttachmentDecryptor<'a, R> {
+impl<'a, R: 'a> AttachmentDecryptor<'a, R> {
     /// Wrap the given reader decrypting all the data we read from it.
     ///
     /// # Arguments
@@ -128,36 +464,78 @@ impl<'a, R: Read + 'a> AttachmentDecryptor<'a, R> {
         input: &'a mut R,
         info: MediaEncryptionInfo,
     ) -> Result<AttachmentDecryptor<'a, R>, DecryptorError> {
-        if info.version != VERSION {
-            return Err(DecryptorError::UnknownVersion);
-        }
-
-        let hash =
-            info.hashes.get("sha256").ok_or(DecryptorError::MissingHash)?.as_bytes().to_owned();
         let key = Zeroizing::from(info.web_key.k.into_inner());
-        let iv = info.iv.into_inner();
-        let iv = GenericArray::from_exact_iter(iv).ok_or(DecryptorError::KeyNonceLength)?;
 
-        let sha = Sha256::default();
-        let aes = Aes256::new_from_slice(&key).map_err(|_| DecryptorError::KeyNonceLength)?;
-        let aes = Aes256Ctr::from_block_cipher(aes, &iv);
+        let backend = match info.version.as_str() {
+            VERSION_V2 => {
+                let hash = info
+                    .hashes
+                    .get("sha256")
+                    .ok_or(DecryptorError::MissingHash)?
+                    .as_bytes()
+                    .to_owned();
+                let iv = info.iv.into_inner();
+                let iv = GenericArray::from_exact_iter(iv).ok_or(DecryptorError::KeyNonceLength)?;
 
-        Ok(AttachmentDecryptor { inner: input, expected_hash: hash, sha, aes })
+                let sha = Sha256::default();
+                let aes = Aes256::new_from_slice(&key).map_err(|_| DecryptorError::KeyNonceLength)?;
+                let aes = Aes256Ctr::from_block_cipher(aes, &iv);
+
+                DecryptorBackend::Ctr { aes, sha, expected_hash: hash }
+            }
+            VERSION_V3 => {
+                if key.len() != KEY_SIZE {
+                    return Err(DecryptorError::KeyNonceLength);
+                }
+
+                let prefix = info.iv.into_inner();
+                let nonce_prefix: [u8; NONCE_PREFIX_SIZE] =
+                    prefix.try_into().map_err(|_| DecryptorError::KeyNonceLength)?;
+
+                let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+
+                DecryptorBackend::Gcm {
+                    state: GcmState {
+                        cipher,
+                        nonce_prefix,
+                        chunk_index: 0,
+                        plaintext_len: info.plaintext_len,
+                        chunk_buf: Vec::new(),
+                    },
+                    output: Vec::new(),
+                    output_pos: 0,
+                    finished: false,
+                    position: 0,
+                }
+            }
+            _ => return Err(DecryptorError::UnknownVersion),
+        };
+
+        Ok(AttachmentDecryptor { inner: input, backend })
     }
 }
 
 /// A wrapper that transparently encrypts anything that implements `Read`.
-pub struct AttachmentEncryptor<'a, R: Read + 'a> {
-    finished: bool,
+pub struct AttachmentEncryptor<'a, R: 'a> {
     inner: &'a mut R,
     web_key: JsonWebKey,
-    iv: Base64,
-    hashes: BTreeMap<String, Base64>,
-    aes: Aes256Ctr,
-    sha: Sha256,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    cipher: Aes256Gcm,
+    chunk_index: u32,
+    /// Plaintext read from `inner` that hasn't been sealed into a chunk yet.
+    read_buffer: Vec<u8>,
+    /// Ciphertext of the current chunk that hasn't been handed out yet.
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Set once `inner` has reported EOF.
+    eof: bool,
+    /// Set once the final, authenticated chunk has been produced.
+    finished: bool,
+    /// The total number of plaintext bytes read from `inner` so far.
+    plaintext_len: u64,
 }
 
-impl<'a, R: 'a + Read + std::fmt::Debug> std::fmt::Debug for AttachmentEncryptor<'a, R> {
+impl<'a, R: 'a + std::fmt::Debug> std::fmt::Debug for AttachmentEncryptor<'a, R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AttachmentEncryptor")
             .field("inner", &self.inner)
@@ -168,24 +546,60 @@ impl<'a, R: 'a + Read + std::fmt::Debug> std::fmt::Debug for AttachmentEncryptor
 
 impl<'a, R: Read + 'a> Read for AttachmentEncryptor<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let read_bytes = self.inner.read(buf)?;
-
-        if read_bytes == 0 {
-            let hash = self.sha.finalize_reset();
-            self.hashes
-                .entry("sha256".to_owned())
-                .or_insert_with(|| Base64::new(hash.as_slice().to_owned()));
-            Ok(0)
-        } else {
-            self.aes.apply_keystream(&mut buf[0..read_bytes]);
-            self.sha.update(&buf[0..read_bytes]);
+        loop {
+            if self.output_pos < self.output.len() {
+                let available = self.output.len() - self.output_pos;
+                let to_copy = available.min(buf.len());
+
+                buf[..to_copy]
+                    .copy_from_slice(&self.output[self.output_pos..self.output_pos + to_copy]);
+                self.output_pos += to_copy;
+
+                return Ok(to_copy);
+            }
+
+            if self.finished {
+                return Ok(0);
+            }
+
+            // Keep reading until we're sure whether this chunk is the last
+            // one: a chunk is only final if `inner` ran dry while the
+            // buffered plaintext still fits in a single chunk.
+            let mut scratch = [0u8; 8192];
+
+            while self.read_buffer.len() <= CHUNK_SIZE && !self.eof {
+                let read_bytes = self.inner.read(&mut scratch)?;
+
+                if read_bytes == 0 {
+                    self.eof = true;
+                } else {
+                    self.read_buffer.extend_from_slice(&scratch[..read_bytes]);
+                }
+            }
+
+            let is_final = self.eof && self.read_buffer.len() <= CHUNK_SIZE;
+            let take = if is_final { self.read_buffer.len() } else { CHUNK_SIZE };
+            let plaintext: Vec<u8> = self.read_buffer.drain(..take).collect();
+
+            let nonce = chunk_nonce(self.nonce_prefix, self.chunk_index, is_final);
+            self.plaintext_len += plaintext.len() as u64;
+            let ciphertext = self
+                .cipher
+                .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+                .expect("AES-256-GCM encryption of an attachment chunk failed");
 
-            Ok(read_bytes)
+            self.chunk_index += 1;
+            self.output = ciphertext;
+            self.output_pos = 0;
+
+            if is_final {
+                self.finished = true;
+            }
         }
     }
 }
 
-impl<'a, R: Read + 'a> AttachmentEncryptor<'a, R> {
+impl<'a, R: 'a> AttachmentEncryptor<'a, R> {
     /// Wrap the given reader encrypting all the data we read from it.
     ///
     /// After all the reads are done, and all the data is encrypted that we wish
@@ -216,50 +630,361 @@ impl<'a, R: Read + 'a> AttachmentEncryptor<'a, R> {
     /// ```
     pub fn new(reader: &'a mut R) -> Self {
         let mut key = Zeroizing::new([0u8; KEY_SIZE]);
-        let mut iv = Zeroizing::new([0u8; IV_SIZE]);
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
 
         getrandom(&mut *key).expect("Can't generate randomness");
-        // Only populate the first 8 bytes with randomness, the rest is 0
-        // initialized for the counter.
-        getrandom(&mut iv[0..8]).expect("Can't generate randomness");
+        getrandom(&mut nonce_prefix).expect("Can't generate randomness");
 
         let web_key = JsonWebKey::from(JsonWebKeyInit {
             kty: "oct".to_owned(),
             key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
-            alg: "A256CTR".to_owned(),
+            alg: "A256GCM".to_owned(),
             k: Base64::new((*key).to_vec()),
             ext: true,
         });
-        let encoded_iv = Base64::new((*iv).to_vec());
-        let iv = GenericArray::from_slice(&*iv);
-        let key = GenericArray::from_slice(&*key);
 
-        let aes = Aes256::new(key);
-        let aes = Aes256Ctr::from_block_cipher(aes, iv);
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&*key));
 
         AttachmentEncryptor {
-            finished: false,
             inner: reader,
-            iv: encoded_iv,
             web_key,
-            hashes: BTreeMap::new(),
-            aes,
-            sha: Sha256::default(),
+            nonce_prefix,
+            cipher,
+            chunk_index: 0,
+            read_buffer: Vec::new(),
+            output: Vec::new(),
+            output_pos: 0,
+            eof: false,
+            finished: false,
+            plaintext_len: 0,
         }
     }
 
     /// Consume the encryptor and get the encryption key.
-    pub fn finish(mut self) -> MediaEncryptionInfo {
-        let hash = self.sha.finalize();
-        self.hashes
-            .entry("sha256".to_owned())
-            .or_insert_with(|| Base64::new(hash.as_slice().to_owned()));
-
+    pub fn finish(self) -> MediaEncryptionInfo {
         MediaEncryptionInfo {
-            version: VERSION.to_string(),
-            hashes: self.hashes,
-            iv: self.iv,
+            version: VERSION_V3.to_owned(),
+            hashes: BTreeMap::new(),
+            iv: Base64::new(self.nonce_prefix.to_vec()),
             web_key: self.web_key,
+            plaintext_len: Some(self.plaintext_len),
+        }
+    }
+}
+
+/// `AsyncRead` implementations that let [`AttachmentEncryptor`] and
+/// [`AttachmentDecryptor`] wrap an asynchronous source, instead of requiring
+/// callers to either block a thread or buffer the whole attachment before
+/// encrypting/decrypting it.
+///
+/// The underlying keystream/chunk state is identical to the sync
+/// implementation above; `poll_read` just drives the inner reader's
+/// `poll_read` instead of blocking on `Read::read`.
+#[cfg(feature = "async-read")]
+mod async_read {
+    use super::{
+        chunk_nonce, AttachmentDecryptor, AttachmentEncryptor, Context, DecryptorBackend,
+        ErrorKind, FuturesAsyncRead, IoError, Nonce, Pin, Poll, CHUNK_SIZE, TAG_SIZE,
+    };
+
+    impl<'a, R: FuturesAsyncRead + Unpin> FuturesAsyncRead for AttachmentDecryptor<'a, R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let this = self.get_mut();
+
+            loop {
+                match &mut this.backend {
+                    DecryptorBackend::Ctr { aes, sha, expected_hash } => {
+                        let read_bytes =
+                            match Pin::new(&mut *this.inner).poll_read(cx, buf) {
+                                Poll::Ready(Ok(n)) => n,
+                                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                                Poll::Pending => return Poll::Pending,
+                            };
+
+                        return Poll::Ready(if read_bytes == 0 {
+                            let hash = sha.finalize_reset();
+
+                            if hash.as_slice() == expected_hash.as_slice() {
+                                Ok(0)
+                            } else {
+                                Err(IoError::new(
+                                    ErrorKind::Other,
+                                    "Hash mismatch while decrypting",
+                                ))
+                            }
+                        } else {
+                            sha.update(&buf[..read_bytes]);
+                            aes.apply_keystream(&mut buf[..read_bytes]);
+                            Ok(read_bytes)
+                        });
+                    }
+                    DecryptorBackend::Gcm { state, output, output_pos, finished, position } => {
+                        if *output_pos < output.len() {
+                            let available = output.len() - *output_pos;
+                            let to_copy = available.min(buf.len());
+
+                            buf[..to_copy]
+                                .copy_from_slice(&output[*output_pos..*output_pos + to_copy]);
+                            *output_pos += to_copy;
+                            *position += to_copy as u64;
+
+                            return Poll::Ready(Ok(to_copy));
+                        }
+
+                        if *finished {
+                            return Poll::Ready(Ok(0));
+                        }
+
+                        let want = CHUNK_SIZE + TAG_SIZE - state.chunk_buf.len();
+                        let mut scratch = vec![0u8; want];
+
+                        let read_bytes =
+                            match Pin::new(&mut *this.inner).poll_read(cx, &mut scratch) {
+                                Poll::Ready(Ok(n)) => n,
+                                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                                Poll::Pending => return Poll::Pending,
+                            };
+
+                        if read_bytes > 0 {
+                            state.chunk_buf.extend_from_slice(&scratch[..read_bytes]);
+                        }
+
+                        match state.try_decrypt_chunk(read_bytes == 0) {
+                            Ok(Some((plaintext, is_final))) => {
+                                *output = plaintext;
+                                *output_pos = 0;
+                                *finished = is_final;
+                            }
+                            Ok(None) => {}
+                            Err(e) => return Poll::Ready(Err(e)),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    impl<'a, R: FuturesAsyncRead + Unpin> FuturesAsyncRead for AttachmentEncryptor<'a, R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let this = self.get_mut();
+
+            loop {
+                if this.output_pos < this.output.len() {
+                    let available = this.output.len() - this.output_pos;
+                    let to_copy = available.min(buf.len());
+
+                    buf[..to_copy]
+                        .copy_from_slice(&this.output[this.output_pos..this.output_pos + to_copy]);
+                    this.output_pos += to_copy;
+
+                    return Poll::Ready(Ok(to_copy));
+                }
+
+                if this.finished {
+                    return Poll::Ready(Ok(0));
+                }
+
+                if this.read_buffer.len() <= CHUNK_SIZE && !this.eof {
+                    let mut scratch = [0u8; 8192];
+
+                    match Pin::new(&mut *this.inner).poll_read(cx, &mut scratch) {
+                        Poll::Ready(Ok(0)) => this.eof = true,
+                        Poll::Ready(Ok(n)) => this.read_buffer.extend_from_slice(&scratch[..n]),
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+
+                    continue;
+                }
+
+                let is_final = this.eof && this.read_buffer.len() <= CHUNK_SIZE;
+                let take = if is_final { this.read_buffer.len() } else { CHUNK_SIZE };
+                let plaintext: Vec<u8> = this.read_buffer.drain(..take).collect();
+
+                let nonce = chunk_nonce(this.nonce_prefix, this.chunk_index, is_final);
+                this.plaintext_len += plaintext.len() as u64;
+                let ciphertext = this
+                    .cipher
+                    .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+                    .expect("AES-256-GCM encryption of an attachment chunk failed");
+
+                this.chunk_index += 1;
+                this.output = ciphertext;
+                this.output_pos = 0;
+
+                if is_final {
+                    this.finished = true;
+                }
+            }
+        }
+    }
+}
+
+/// `tokio::io::AsyncRead` variants of the same wrappers, for callers already
+/// standardized on tokio's IO traits instead of `futures_util`'s.
+#[cfg(feature = "tokio")]
+mod tokio_read {
+    use super::{
+        chunk_nonce, AttachmentDecryptor, AttachmentEncryptor, Context, DecryptorBackend,
+        ErrorKind, IoError, Nonce, Pin, Poll, ReadBuf, TokioAsyncRead, CHUNK_SIZE, TAG_SIZE,
+    };
+
+    impl<'a, R: TokioAsyncRead + Unpin> TokioAsyncRead for AttachmentDecryptor<'a, R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+
+            loop {
+                match &mut this.backend {
+                    DecryptorBackend::Ctr { aes, sha, expected_hash } => {
+                        let filled_before = buf.filled().len();
+
+                        match Pin::new(&mut *this.inner).poll_read(cx, buf) {
+                            Poll::Ready(Ok(())) => {}
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => return Poll::Pending,
+                        }
+
+                        let filled = &mut buf.filled_mut()[filled_before..];
+
+                        return Poll::Ready(if filled.is_empty() {
+                            let hash = sha.finalize_reset();
+
+                            if hash.as_slice() == expected_hash.as_slice() {
+                                Ok(())
+                            } else {
+                                Err(IoError::new(
+                                    ErrorKind::Other,
+                                    "Hash mismatch while decrypting",
+                                ))
+                            }
+                        } else {
+                            sha.update(filled);
+                            aes.apply_keystream(filled);
+                            Ok(())
+                        });
+                    }
+                    DecryptorBackend::Gcm { state, output, output_pos, finished, position } => {
+                        if *output_pos < output.len() {
+                            let available = output.len() - *output_pos;
+                            let to_copy = available.min(buf.remaining());
+
+                            buf.put_slice(&output[*output_pos..*output_pos + to_copy]);
+                            *output_pos += to_copy;
+                            *position += to_copy as u64;
+
+                            return Poll::Ready(Ok(()));
+                        }
+
+                        if *finished {
+                            return Poll::Ready(Ok(()));
+                        }
+
+                        let want = CHUNK_SIZE + TAG_SIZE - state.chunk_buf.len();
+                        let mut scratch = vec![0u8; want];
+                        let mut scratch_buf = ReadBuf::new(&mut scratch);
+
+                        match Pin::new(&mut *this.inner).poll_read(cx, &mut scratch_buf) {
+                            Poll::Ready(Ok(())) => {}
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => return Poll::Pending,
+                        }
+
+                        let read_bytes = scratch_buf.filled().len();
+
+                        if read_bytes > 0 {
+                            state.chunk_buf.extend_from_slice(&scratch[..read_bytes]);
+                        }
+
+                        match state.try_decrypt_chunk(read_bytes == 0) {
+                            Ok(Some((plaintext, is_final))) => {
+                                *output = plaintext;
+                                *output_pos = 0;
+                                *finished = is_final;
+                            }
+                            Ok(None) => {}
+                            Err(e) => return Poll::Ready(Err(e)),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    impl<'a, R: TokioAsyncRead + Unpin> TokioAsyncRead for AttachmentEncryptor<'a, R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+
+            loop {
+                if this.output_pos < this.output.len() {
+                    let available = this.output.len() - this.output_pos;
+                    let to_copy = available.min(buf.remaining());
+
+                    buf.put_slice(&this.output[this.output_pos..this.output_pos + to_copy]);
+                    this.output_pos += to_copy;
+
+                    return Poll::Ready(Ok(()));
+                }
+
+                if this.finished {
+                    return Poll::Ready(Ok(()));
+                }
+
+                if this.read_buffer.len() <= CHUNK_SIZE && !this.eof {
+                    let mut scratch = [0u8; 8192];
+                    let mut scratch_buf = ReadBuf::new(&mut scratch);
+
+                    match Pin::new(&mut *this.inner).poll_read(cx, &mut scratch_buf) {
+                        Poll::Ready(Ok(())) => {}
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+
+                    let read_bytes = scratch_buf.filled().len();
+
+                    if read_bytes == 0 {
+                        this.eof = true;
+                    } else {
+                        this.read_buffer.extend_from_slice(&scratch[..read_bytes]);
+                    }
+
+                    continue;
+                }
+
+                let is_final = this.eof && this.read_buffer.len() <= CHUNK_SIZE;
+                let take = if is_final { this.read_buffer.len() } else { CHUNK_SIZE };
+                let plaintext: Vec<u8> = this.read_buffer.drain(..take).collect();
+
+                let nonce = chunk_nonce(this.nonce_prefix, this.chunk_index, is_final);
+                this.plaintext_len += plaintext.len() as u64;
+                let ciphertext = this
+                    .cipher
+                    .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+                    .expect("AES-256-GCM encryption of an attachment chunk failed");
+
+                this.chunk_index += 1;
+                this.output = ciphertext;
+                this.output_pos = 0;
+
+                if is_final {
+                    this.finished = true;
+                }
+            }
         }
     }
 }
@@ -274,24 +999,202 @@ pub struct MediaEncryptionInfo {
     /// The web key that was used to encrypt the file.
     pub web_key: JsonWebKey,
     /// The initialization vector that was used to encrypt the file.
+    ///
+    /// For `v2` this is the 16-byte AES-CTR IV. For `v3` this holds the
+    /// 8-byte random nonce prefix that, combined with each chunk's index, is
+    /// used to build that chunk's AES-GCM nonce.
     pub iv: Base64,
     /// The hashes that can be used to check the validity of the file.
+    ///
+    /// Only populated for `v2`; `v3` chunks are authenticated individually
+    /// and don't need a whole-file hash.
     pub hashes: BTreeMap<String, Base64>,
+    /// The total length, in bytes, of the plaintext.
+    ///
+    /// Only populated for `v3`. Lets a caller compute valid byte ranges, and
+    /// is required to resolve `SeekFrom::End` when seeking an
+    /// [`AttachmentDecryptor`] -- a `v3` blob that omits it can still be read
+    /// start-to-end or seeked with `SeekFrom::Start`/`SeekFrom::Current`.
+    ///
+    /// This value isn't bound into any GCM tag, so it's taken on trust; a
+    /// `SeekFrom::End` seek against a tampered length may land on the wrong
+    /// chunk, but every chunk's content remains independently authenticated
+    /// regardless of where a seek lands.
+    #[serde(rename = "plaintext_len", skip_serializing_if = "Option::is_none", default)]
+    pub plaintext_len: Option<u64>,
 }
 
 impl From<EncryptedFile> for MediaEncryptionInfo {
     fn from(file: EncryptedFile) -> Self {
-        Self { version: file.v, web_key: file.key, iv: file.iv, hashes: file.hashes }
+        Self {
+            version: file.v,
+            web_key: file.key,
+            iv: file.iv,
+            hashes: file.hashes,
+            plaintext_len: None,
+        }
+    }
+}
+
+impl MediaEncryptionInfo {
+    /// Serialize this info and encrypt it under a key derived from
+    /// `passphrase`, so it can be safely stored or transferred alongside the
+    /// ciphertext it decrypts.
+    ///
+    /// The passphrase is stretched into a 32-byte key with Argon2id and a
+    /// fresh random salt; the serialized info is then sealed with
+    /// AES-256-GCM under a fresh random nonce. The output is a
+    /// self-describing frame: `magic || version || salt || nonce ||
+    /// ciphertext || tag`.
+    pub fn export_encrypted(&self, passphrase: &str) -> Vec<u8> {
+        let mut salt = [0u8; EXPORT_SALT_SIZE];
+        let mut nonce = [0u8; EXPORT_NONCE_SIZE];
+
+        getrandom(&mut salt).expect("Can't generate randomness");
+        getrandom(&mut nonce).expect("Can't generate randomness");
+
+        let key = derive_export_key(passphrase, &salt);
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&*key));
+
+        let plaintext = Zeroizing::new(
+            serde_json::to_vec(self).expect("MediaEncryptionInfo can always be serialized"),
+        );
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .expect("AES-256-GCM encryption of an exported media key failed");
+
+        let mut frame = Vec::with_capacity(
+            EXPORT_MAGIC.len() + 1 + EXPORT_SALT_SIZE + EXPORT_NONCE_SIZE + ciphertext.len(),
+        );
+        frame.extend_from_slice(EXPORT_MAGIC);
+        frame.push(EXPORT_VERSION);
+        frame.extend_from_slice(&salt);
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&ciphertext);
+
+        frame
+    }
+
+    /// Reverse of [`export_encrypted()`](Self::export_encrypted): re-derive
+    /// the wrapping key from `passphrase` and the salt stored in the frame,
+    /// then authenticate and decrypt the info.
+    pub fn import_encrypted(bytes: &[u8], passphrase: &str) -> Result<Self, DecryptorError> {
+        let header_len = EXPORT_MAGIC.len() + 1 + EXPORT_SALT_SIZE + EXPORT_NONCE_SIZE;
+
+        if bytes.len() < header_len || &bytes[..EXPORT_MAGIC.len()] != EXPORT_MAGIC {
+            return Err(DecryptorError::InvalidExportFormat);
+        }
+
+        let mut offset = EXPORT_MAGIC.len();
+        let version = bytes[offset];
+        offset += 1;
+
+        if version != EXPORT_VERSION {
+            return Err(DecryptorError::InvalidExportFormat);
+        }
+
+        let salt = &bytes[offset..offset + EXPORT_SALT_SIZE];
+        offset += EXPORT_SALT_SIZE;
+        let nonce = &bytes[offset..offset + EXPORT_NONCE_SIZE];
+        offset += EXPORT_NONCE_SIZE;
+        let ciphertext = &bytes[offset..];
+
+        let key = derive_export_key(passphrase, salt);
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&*key));
+
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| DecryptorError::InvalidPassphrase)?,
+        );
+
+        serde_json::from_slice(&plaintext).map_err(|_| DecryptorError::InvalidExportFormat)
+    }
+
+    /// Encode this info as a PGP-style armored block: a JSON serialization of
+    /// `self` (the same encoding [`export_encrypted()`](Self::export_encrypted)
+    /// uses), Base85-encoded and wrapped at a fixed line width, with a
+    /// trailing CRC-24 checksum line.
+    ///
+    /// Unlike `export_encrypted()` this isn't encrypted, only made robust
+    /// against mangling by channels that don't tolerate arbitrary binary or
+    /// the exact shape of base64/JSON -- the checksum, computed over the
+    /// Base85-decoded bytes, lets
+    /// [`from_armored_string()`](Self::from_armored_string) detect corruption
+    /// before attempting to deserialize them.
+    pub fn to_armored_string(&self) -> String {
+        let raw =
+            serde_json::to_vec(self).expect("MediaEncryptionInfo can always be serialized");
+        let body = base85::encode(&raw);
+
+        let mut armored = String::new();
+        armored.push_str(ARMOR_HEADER);
+        armored.push_str("\n\n");
+
+        for line in body.as_bytes().chunks(ARMOR_LINE_WIDTH) {
+            armored.push_str(std::str::from_utf8(line).expect("Base85 output is ASCII"));
+            armored.push('\n');
+        }
+
+        let checksum = crc24(&raw).to_be_bytes();
+        armored.push('=');
+        armored.push_str(&base85::encode(&checksum[1..]));
+        armored.push('\n');
+        armored.push_str(ARMOR_FOOTER);
+        armored.push('\n');
+
+        armored
+    }
+
+    /// Parse a block produced by
+    /// [`to_armored_string()`](Self::to_armored_string), verifying its
+    /// checksum against the Base85-decoded body before deserializing it.
+    pub fn from_armored_string(text: &str) -> Result<Self, DecryptorError> {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+
+        if lines.next() != Some(ARMOR_HEADER) {
+            return Err(DecryptorError::InvalidExportFormat);
+        }
+
+        let rest: Vec<&str> = lines.collect();
+        let (footer, body_and_checksum) =
+            rest.split_last().ok_or(DecryptorError::InvalidExportFormat)?;
+
+        if *footer != ARMOR_FOOTER {
+            return Err(DecryptorError::InvalidExportFormat);
+        }
+
+        let (checksum_line, body_lines) =
+            body_and_checksum.split_last().ok_or(DecryptorError::InvalidExportFormat)?;
+        let checksum_body =
+            checksum_line.strip_prefix('=').ok_or(DecryptorError::InvalidExportFormat)?;
+
+        let raw = base85::decode(&body_lines.concat())
+            .map_err(|_| DecryptorError::InvalidExportFormat)?;
+        let checksum_bytes =
+            base85::decode(checksum_body).map_err(|_| DecryptorError::InvalidExportFormat)?;
+
+        let [b0, b1, b2]: [u8; 3] =
+            checksum_bytes.try_into().map_err(|_| DecryptorError::InvalidExportFormat)?;
+        let expected_checksum = u32::from_be_bytes([0, b0, b1, b2]);
+
+        if crc24(&raw) != expected_checksum {
+            return Err(DecryptorError::ArmorChecksum);
+        }
+
+        serde_json::from_slice(&raw).map_err(|_| DecryptorError::InvalidExportFormat)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::io::{Cursor, Read};
+    use std::io::{Cursor, Read, Seek, SeekFrom};
 
     use serde_json::json;
 
-    use super::{AttachmentDecryptor, AttachmentEncryptor, MediaEncryptionInfo};
+    use super::{
+        AttachmentDecryptor, AttachmentEncryptor, DecryptorError, MediaEncryptionInfo, CHUNK_SIZE,
+    };
 
     const EXAMPLE_DATA: &[u8] = &[
         179, 154, 118, 127, 186, 127, 110, 33, 203, 33, 33, 134, 67, 100, 173, 46, 235, 27, 215,
@@ -341,6 +1244,28 @@ mod test {
         assert_eq!(data, decrypted);
     }
 
+    #[test]
+    fn encrypt_decrypt_cycle_multiple_chunks() {
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 123)).map(|i| (i % 256) as u8).collect();
+        let mut cursor = Cursor::new(data.clone());
+
+        let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted).unwrap();
+        let info = encryptor.finish();
+
+        // Three chunks: two full ones and a short final one, each with its
+        // own authentication tag.
+        assert_eq!(encrypted.len(), data.len() + 3 * 16);
+
+        let mut cursor = Cursor::new(encrypted);
+        let mut decryptor = AttachmentDecryptor::new(&mut cursor, info).unwrap();
+        let mut decrypted = Vec::new();
+        decryptor.read_to_end(&mut decrypted).unwrap();
+
+        assert_eq!(data, decrypted);
+    }
+
     #[test]
     fn real_decrypt() {
         let mut cursor = Cursor::new(EXAMPLE_DATA.to_vec());
@@ -365,4 +1290,204 @@ mod test {
 
         assert!(decryptor.read_to_end(&mut decrypted_data).is_err())
     }
+
+    #[test]
+    fn v3_detects_truncation() {
+        let data = "Hello world, this is a message that fits in a single chunk".to_owned();
+        let mut cursor = Cursor::new(data);
+
+        let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted).unwrap();
+        let info = encryptor.finish();
+
+        // Drop the last byte, which is part of the only (final) chunk's tag,
+        // so the decryptor never sees an authenticated final chunk.
+        encrypted.truncate(encrypted.len() - 1);
+
+        let mut cursor = Cursor::new(encrypted);
+        let mut decryptor = AttachmentDecryptor::new(&mut cursor, info).unwrap();
+        let mut decrypted = Vec::new();
+
+        assert!(decryptor.read_to_end(&mut decrypted).is_err());
+    }
+
+    #[test]
+    fn v3_seek_to_arbitrary_chunk() {
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 123)).map(|i| (i % 256) as u8).collect();
+        let mut cursor = Cursor::new(data.clone());
+
+        let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted).unwrap();
+        let info = encryptor.finish();
+        assert_eq!(info.plaintext_len, Some(data.len() as u64));
+
+        let offset = CHUNK_SIZE + 50;
+        let mut cursor = Cursor::new(encrypted);
+        let mut decryptor = AttachmentDecryptor::new(&mut cursor, info).unwrap();
+
+        assert_eq!(decryptor.seek(SeekFrom::Start(offset as u64)).unwrap(), offset as u64);
+
+        let mut decrypted = Vec::new();
+        decryptor.read_to_end(&mut decrypted).unwrap();
+
+        assert_eq!(decrypted, data[offset..]);
+    }
+
+    #[test]
+    fn v3_seek_from_end() {
+        let data: Vec<u8> = (0..(CHUNK_SIZE + 10)).map(|i| (i % 256) as u8).collect();
+        let mut cursor = Cursor::new(data.clone());
+
+        let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted).unwrap();
+        let info = encryptor.finish();
+
+        let mut cursor = Cursor::new(encrypted);
+        let mut decryptor = AttachmentDecryptor::new(&mut cursor, info).unwrap();
+
+        decryptor.seek(SeekFrom::End(-5)).unwrap();
+
+        let mut decrypted = Vec::new();
+        decryptor.read_to_end(&mut decrypted).unwrap();
+
+        assert_eq!(decrypted, data[data.len() - 5..]);
+    }
+
+    #[test]
+    fn v3_detects_tampering() {
+        let data = "Hello world".to_owned();
+        let mut cursor = Cursor::new(data);
+
+        let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted).unwrap();
+        let info = encryptor.finish();
+
+        encrypted[0] ^= 1;
+
+        let mut cursor = Cursor::new(encrypted);
+        let mut decryptor = AttachmentDecryptor::new(&mut cursor, info).unwrap();
+        let mut decrypted = Vec::new();
+
+        assert!(decryptor.read_to_end(&mut decrypted).is_err());
+    }
+
+    #[test]
+    fn export_import_encrypted_cycle() {
+        let info = example_key();
+        let exported = info.export_encrypted("correct horse battery staple");
+
+        let imported =
+            MediaEncryptionInfo::import_encrypted(&exported, "correct horse battery staple")
+                .unwrap();
+
+        assert_eq!(imported.version, "v2");
+        assert_eq!(imported.web_key.k, example_key().web_key.k);
+    }
+
+    #[test]
+    fn import_encrypted_wrong_passphrase() {
+        let info = example_key();
+        let exported = info.export_encrypted("correct horse battery staple");
+
+        assert!(matches!(
+            MediaEncryptionInfo::import_encrypted(&exported, "wrong passphrase"),
+            Err(DecryptorError::InvalidPassphrase)
+        ));
+    }
+
+    #[test]
+    fn import_encrypted_tampered() {
+        let info = example_key();
+        let mut exported = info.export_encrypted("correct horse battery staple");
+        let last = exported.len() - 1;
+        exported[last] ^= 1;
+
+        assert!(matches!(
+            MediaEncryptionInfo::import_encrypted(&exported, "correct horse battery staple"),
+            Err(DecryptorError::InvalidPassphrase)
+        ));
+    }
+
+    #[test]
+    fn armored_round_trip() {
+        let info = example_key();
+        let armored = info.to_armored_string();
+
+        assert!(armored.starts_with("-----BEGIN MATRIX MEDIA KEY-----\n"));
+        assert!(armored.trim_end().ends_with("-----END MATRIX MEDIA KEY-----"));
+
+        let parsed = MediaEncryptionInfo::from_armored_string(&armored).unwrap();
+
+        assert_eq!(parsed.version, "v2");
+        assert_eq!(parsed.web_key.k, example_key().web_key.k);
+    }
+
+    #[test]
+    fn armored_detects_checksum_mismatch() {
+        let info = example_key();
+        let mut armored = info.to_armored_string();
+
+        // Flip a character in the body, leaving the checksum line untouched.
+        let body_char = armored.find('\n').unwrap() + 2;
+        let mut chars: Vec<char> = armored.chars().collect();
+        chars[body_char] = if chars[body_char] == 'a' { 'b' } else { 'a' };
+        armored = chars.into_iter().collect();
+
+        assert!(matches!(
+            MediaEncryptionInfo::from_armored_string(&armored),
+            Err(DecryptorError::ArmorChecksum)
+        ));
+    }
+
+    #[cfg(feature = "async-read")]
+    #[test]
+    fn async_encrypt_decrypt_cycle() {
+        use futures_util::io::AsyncReadExt;
+
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 123)).map(|i| (i % 256) as u8).collect();
+
+        futures_executor::block_on(async {
+            let mut cursor = Cursor::new(data.clone());
+            let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+
+            let mut encrypted = Vec::new();
+            encryptor.read_to_end(&mut encrypted).await.unwrap();
+            let info = encryptor.finish();
+
+            let mut cursor = Cursor::new(encrypted);
+            let mut decryptor = AttachmentDecryptor::new(&mut cursor, info).unwrap();
+
+            let mut decrypted = Vec::new();
+            decryptor.read_to_end(&mut decrypted).await.unwrap();
+
+            assert_eq!(data, decrypted);
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_encrypt_decrypt_cycle() {
+        use tokio::io::AsyncReadExt;
+
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 2 + 123)).map(|i| (i % 256) as u8).collect();
+
+        let mut cursor = Cursor::new(data.clone());
+        let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted).await.unwrap();
+        let info = encryptor.finish();
+
+        let mut cursor = Cursor::new(encrypted);
+        let mut decryptor = AttachmentDecryptor::new(&mut cursor, info).unwrap();
+
+        let mut decrypted = Vec::new();
+        decryptor.read_to_end(&mut decrypted).await.unwrap();
+
+        assert_eq!(data, decrypted);
+    }
 }